@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use async_channel::{Receiver, Sender};
+
+/// Subscribing to this topic delivers every `MuteChanged` event, regardless of which node
+/// it's for.
+pub const ANY_TOPIC: &str = "any";
+
+#[derive(Debug, Clone)]
+pub struct MuteChanged {
+    pub global_id: u32,
+    pub name: String,
+    pub muted: bool,
+}
+
+struct Subscriber {
+    id: u64,
+    sender: Sender<MuteChanged>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    subscribers: HashMap<String, Vec<Subscriber>>,
+}
+
+/// An in-process pub/sub bus for mute-state-change notifications, keyed by topic (a node's
+/// `global_id` as a string, or [`ANY_TOPIC`]). Lets things like the control socket or an
+/// on-screen indicator react to mute changes without polling `Nodes`.
+#[derive(Clone, Default)]
+pub struct EventSys {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl EventSys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes `event` to `topic` and to [`ANY_TOPIC`], dropping any subscriber whose
+    /// receiver has been closed.
+    pub fn publish(&self, topic: &str, event: MuteChanged) {
+        self.publish_to(topic, &event);
+        if topic != ANY_TOPIC {
+            self.publish_to(ANY_TOPIC, &event);
+        }
+    }
+
+    fn publish_to(&self, topic: &str, event: &MuteChanged) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(subs) = inner.subscribers.get_mut(topic) {
+            subs.retain(|sub| sub.sender.try_send(event.clone()).is_ok());
+        }
+    }
+
+    /// Subscribes to `topic`, returning a receiver for future events and an RAII guard that
+    /// removes the subscription from the bus when dropped.
+    pub fn subscribe(&self, topic: impl Into<String>) -> (Receiver<MuteChanged>, Subscription) {
+        let topic = topic.into();
+        let (sender, receiver) = async_channel::unbounded();
+
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.subscribers.entry(topic.clone()).or_default().push(Subscriber { id, sender });
+        drop(inner);
+
+        (receiver, Subscription { inner: self.inner.clone(), topic, id })
+    }
+}
+
+pub struct Subscription {
+    inner: Rc<RefCell<Inner>>,
+    topic: String,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(subs) = self.inner.borrow_mut().subscribers.get_mut(&self.topic) {
+            subs.retain(|sub| sub.id != self.id);
+        }
+    }
+}
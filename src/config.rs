@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use evdev::Key;
+use serde::Deserialize;
+
+use crate::reconnect::Backoff;
+use crate::KeyType;
+
+/// Parsed contents of a node-mapping config file.
+pub struct Config {
+    pub release_delay: u64,
+    pub nodes: Vec<(String, (KeyType, Key))>,
+    /// Per-node `release-delay` overrides, keyed by `node.name`.
+    pub release_delay_overrides: HashMap<String, u64>,
+    pub backoff: Backoff,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default, rename = "release-delay")]
+    release_delay: u64,
+    #[serde(default)]
+    node: Vec<RawNode>,
+    #[serde(default)]
+    backoff: RawBackoff,
+}
+
+/// `[backoff]` knobs for the PipeWire reconnect loop; see [`crate::reconnect::Backoff`].
+///
+/// Each field defaults independently (to the same values as [`Backoff::default`]) so a
+/// `[backoff]` table only needs to set the knob it's overriding, e.g. `factor = 3.0` alone.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBackoff {
+    #[serde(default = "default_base_ms", rename = "base-ms")]
+    base_ms: u64,
+    #[serde(default = "default_factor")]
+    factor: f64,
+    #[serde(default = "default_max_ms", rename = "max-ms")]
+    max_ms: u64,
+    #[serde(default = "default_jitter")]
+    jitter: bool,
+}
+
+fn default_base_ms() -> u64 {
+    Backoff::default().base().as_millis() as u64
+}
+
+fn default_factor() -> f64 {
+    Backoff::default().factor()
+}
+
+fn default_max_ms() -> u64 {
+    Backoff::default().max().as_millis() as u64
+}
+
+fn default_jitter() -> bool {
+    Backoff::default().jitter()
+}
+
+impl Default for RawBackoff {
+    fn default() -> Self {
+        let default = Backoff::default();
+        RawBackoff {
+            base_ms: default.base().as_millis() as u64,
+            factor: default.factor(),
+            max_ms: default.max().as_millis() as u64,
+            jitter: default.jitter(),
+        }
+    }
+}
+
+impl From<RawBackoff> for Backoff {
+    fn from(raw: RawBackoff) -> Self {
+        Backoff::new(
+            Duration::from_millis(raw.base_ms),
+            raw.factor,
+            Duration::from_millis(raw.max_ms),
+            raw.jitter,
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNode {
+    name: String,
+    key: String,
+    #[serde(rename = "type")]
+    key_type: RawKeyType,
+    #[serde(default, rename = "release-delay")]
+    release_delay: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawKeyType {
+    Hold,
+    Toggle,
+}
+
+impl From<RawKeyType> for KeyType {
+    fn from(raw: RawKeyType) -> Self {
+        match raw {
+            RawKeyType::Hold => KeyType::HOLD,
+            RawKeyType::Toggle => KeyType::TOGGLE,
+        }
+    }
+}
+
+/// Loads and parses a TOML node-mapping config from `path`.
+///
+/// Entries look like:
+/// ```toml
+/// release-delay = 200
+///
+/// [[node]]
+/// name = "alsa_input.usb-foo"
+/// key = "KEY_F13"
+/// type = "hold"
+/// release-delay = 50
+///
+/// [backoff]
+/// base-ms = 100
+/// factor = 2.0
+/// max-ms = 30000
+/// jitter = true
+/// ```
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+    let raw: RawConfig = toml::from_str(&text)
+        .map_err(|e| format!("failed to parse config {}: {}", path.display(), e))?;
+
+    let mut nodes = Vec::with_capacity(raw.node.len());
+    let mut release_delay_overrides = HashMap::new();
+    for entry in raw.node {
+        let key = parse_key(&entry.key)?;
+        if let Some(delay) = entry.release_delay {
+            release_delay_overrides.insert(entry.name.clone(), delay);
+        }
+        nodes.push((entry.name, (entry.key_type.into(), key)));
+    }
+
+    Ok(Config {
+        release_delay: raw.release_delay,
+        nodes,
+        release_delay_overrides,
+        backoff: raw.backoff.into(),
+    })
+}
+
+fn parse_key(s: &str) -> Result<Key, String> {
+    if let Ok(code) = s.parse::<u16>() {
+        return Ok(Key::new(code));
+    }
+    Key::from_str(s).map_err(|_| format!("unknown key \"{}\"", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn parse_key_accepts_numeric_codes() {
+        assert_eq!(parse_key("1").unwrap(), Key::new(1));
+    }
+
+    #[test]
+    fn parse_key_accepts_name_codes() {
+        assert_eq!(parse_key("KEY_F13").unwrap(), Key::from_str("KEY_F13").unwrap());
+    }
+
+    #[test]
+    fn parse_key_rejects_garbage() {
+        assert!(parse_key("not_a_key").is_err());
+    }
+
+    fn write_temp_config(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "pw-push-to-talk-test-{}-{:?}.toml",
+            std::process::id(),
+            std::thread::current().id(),
+        ));
+        let mut file = fs::File::create(&path).expect("failed to create temp config");
+        file.write_all(contents.as_bytes()).expect("failed to write temp config");
+        path
+    }
+
+    #[test]
+    fn load_applies_per_node_release_delay_override() {
+        let path = write_temp_config(
+            r#"
+            release-delay = 200
+
+            [[node]]
+            name = "alsa_input.usb-foo"
+            key = "KEY_F13"
+            type = "hold"
+            release-delay = 50
+            "#,
+        );
+        let cfg = load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(cfg.release_delay, 200);
+        assert_eq!(cfg.release_delay_overrides.get("alsa_input.usb-foo"), Some(&50));
+    }
+
+    #[test]
+    fn load_rejects_unknown_fields() {
+        let path = write_temp_config(
+            r#"
+            [[node]]
+            name = "foo"
+            key = "KEY_F13"
+            type = "hold"
+            typo-field = true
+            "#,
+        );
+        let result = load(&path);
+        fs::remove_file(&path).ok();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn load_partial_backoff_table_fills_in_defaults() {
+        let path = write_temp_config(
+            r#"
+            [backoff]
+            factor = 3.0
+            "#,
+        );
+        let cfg = load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        let default = Backoff::default();
+        assert_eq!(cfg.backoff.base(), default.base());
+        assert_eq!(cfg.backoff.factor(), 3.0);
+        assert_eq!(cfg.backoff.max(), default.max());
+        assert_eq!(cfg.backoff.jitter(), default.jitter());
+    }
+}
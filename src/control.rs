@@ -0,0 +1,290 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use async_executor::LocalExecutor;
+use async_net::unix::{UnixListener, UnixStream};
+use evdev::Key;
+use futures_lite::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+
+use crate::bus::{self, EventSys};
+use crate::taskgroup::Stopper;
+use crate::{node_set_mute, do_roundtrip, register_node_listener, CoreHandle, KeyType, MutePods, Nodes, Mappings, RegistryHandle};
+
+/// Shared state the control socket needs to read and mutate.
+#[derive(Clone)]
+pub struct ControlState {
+    pub nodes: Nodes,
+    pub mappings: Mappings,
+    pub default_release_delay: Rc<Cell<u64>>,
+    pub release_delay_overrides: Rc<RefCell<HashMap<String, u64>>>,
+    pub pods: Arc<MutePods>,
+    pub core: CoreHandle,
+    pub registry: RegistryHandle,
+    pub bus: EventSys,
+}
+
+/// Identifies a bound node for the `Mute` and `Subscribe` commands.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NodeTarget {
+    Name(String),
+    GlobalId(u32),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    List,
+    Mute { target: NodeTarget, muted: bool },
+    AddMapping { name: String, key_type: KeyType, key_code: u16 },
+    RemoveMapping { name: String },
+    SetReleaseDelay { millis: u64 },
+    /// Streams every [`bus::MuteChanged`] event for `target` (or all nodes, if `None`) back as
+    /// `Response::MuteEvent` until the client disconnects. Takes over the connection: no further
+    /// requests are read on it once this is sent.
+    Subscribe { target: Option<NodeTarget> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub name: String,
+    pub global_id: u32,
+    pub key_type: KeyType,
+    pub key_code: u16,
+    pub muted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Nodes(Vec<NodeInfo>),
+    Ok,
+    Error(String),
+    MuteEvent { global_id: u32, name: String, muted: bool },
+}
+
+/// Listens on `path` for control connections and serves them until the [`Stopper`] fires.
+/// Stale sockets left behind by an unclean exit are removed first, the way most daemons that
+/// bind a unix socket do; the same happens again on a graceful shutdown so the next start
+/// doesn't have to.
+pub async fn serve(path: PathBuf, state: ControlState, ex: Rc<LocalExecutor<'static>>, stopper: Stopper) {
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .unwrap_or_else(|e| panic!("failed to bind control socket {}: {}", path.display(), e));
+
+    loop {
+        enum Event {
+            Accepted(io::Result<(UnixStream, async_net::unix::SocketAddr)>),
+            Stop,
+        }
+        let event = futures_lite::future::or(
+            async { Event::Accepted(listener.accept().await) },
+            async { stopper.stopped().await; Event::Stop },
+        ).await;
+
+        match event {
+            Event::Accepted(Ok((stream, _))) => {
+                let state = state.clone();
+                ex.spawn(handle_connection(stream, state)).detach();
+            }
+            Event::Accepted(Err(err)) => eprintln!("control socket accept failed: {}", err),
+            Event::Stop => {
+                let _ = std::fs::remove_file(&path);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: UnixStream, state: ControlState) {
+    loop {
+        let request = match read_frame(&mut stream).await {
+            Ok(Some(bytes)) => match bincode::deserialize::<Request>(&bytes) {
+                Ok(request) => request,
+                Err(err) => {
+                    let _ = respond(&mut stream, &Response::Error(format!("bad request: {}", err))).await;
+                    continue;
+                }
+            },
+            Ok(None) => return, // client disconnected
+            Err(err) => {
+                eprintln!("control connection read error: {}", err);
+                return;
+            }
+        };
+
+        if let Request::Subscribe { target } = request {
+            handle_subscribe(&mut stream, &state, target).await;
+            return;
+        }
+
+        let response = handle_request(request, &state).await;
+        if respond(&mut stream, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Streams `MuteChanged` events for `target` back over `stream`, one `Response::MuteEvent`
+/// frame per event, until the subscription's receiver errors (the bus was dropped, which
+/// doesn't otherwise happen) or writing to the client fails (it disconnected).
+async fn handle_subscribe(stream: &mut UnixStream, state: &ControlState, target: Option<NodeTarget>) {
+    let topic = match &target {
+        None => bus::ANY_TOPIC.to_string(),
+        Some(NodeTarget::GlobalId(id)) => id.to_string(),
+        Some(NodeTarget::Name(name)) => {
+            match state.nodes.borrow().iter().find(|(node, _)| node.name == *name) {
+                Some((node, _)) => node.global_id.to_string(),
+                None => {
+                    let _ = respond(stream, &Response::Error(format!("no bound node matches {:?}", target))).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let (receiver, _subscription) = state.bus.subscribe(topic);
+    if respond(stream, &Response::Ok).await.is_err() {
+        return;
+    }
+
+    while let Ok(event) = receiver.recv().await {
+        let response = Response::MuteEvent {
+            global_id: event.global_id,
+            name: event.name,
+            muted: event.muted,
+        };
+        if respond(stream, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle_request(request: Request, state: &ControlState) -> Response {
+    match request {
+        Request::List => {
+            let infos = state.nodes.borrow().iter().map(|(node, (key_type, key))| NodeInfo {
+                name: node.name.clone(),
+                global_id: node.global_id,
+                key_type: *key_type,
+                key_code: key.code(),
+                muted: node.muted.get(),
+            }).collect();
+            Response::Nodes(infos)
+        }
+        Request::Mute { target, muted } => {
+            // Clone the Rc and drop the borrow before awaiting; the roundtrip below yields to
+            // the executor, and holding a `Ref` across that would panic if another task tries
+            // to mutate `nodes` in the meantime.
+            let found = state.nodes.borrow().iter()
+                .find(|(node, _)| matches_target(node, &target))
+                .map(|(node, _)| node.clone());
+            match found {
+                Some(node) => {
+                    node_set_mute(&node, muted, &state.pods, &state.bus);
+                    if let Some(core) = state.core.borrow().clone() {
+                        do_roundtrip(&core).await;
+                    }
+                    Response::Ok
+                }
+                None => Response::Error(format!("no bound node matches {:?}", target)),
+            }
+        }
+        Request::AddMapping { name, key_type, key_code } => {
+            state.mappings.borrow_mut().push((name.clone(), (key_type, Key(key_code))));
+
+            // `mappings` only drives the persistent registry listener for nodes PipeWire
+            // announces from here on. To also bind a node it already announced before this
+            // mapping existed, register a throwaway listener on the live registry: PipeWire
+            // re-fires `global()` for every currently-known global as soon as a listener is
+            // added, so this retroactively matches it the same way the persistent listener
+            // would if the node showed up now. `register_node_listener`'s de-dupe guard keeps
+            // this from double-binding anything the persistent listener already bound.
+            if let Some(registry) = state.registry.borrow().clone() {
+                let temp_mapping: Mappings = Rc::new(RefCell::new(vec![(name, (key_type, Key(key_code)))]));
+                let _temp_listener = register_node_listener(
+                    &registry,
+                    temp_mapping,
+                    state.default_release_delay.clone(),
+                    state.release_delay_overrides.clone(),
+                    state.nodes.clone(),
+                    state.pods.clone(),
+                    state.bus.clone(),
+                );
+                if let Some(core) = state.core.borrow().clone() {
+                    do_roundtrip(&core).await;
+                }
+            }
+            Response::Ok
+        }
+        Request::RemoveMapping { name } => {
+            state.mappings.borrow_mut().retain(|(name_in, _)| *name_in != name);
+
+            // Unbind and unmute whatever was bound under this mapping; otherwise a live node
+            // stays in `nodes` (and possibly muted) with nothing left mapping to it.
+            let unbound: Vec<_> = {
+                let mut nodes = state.nodes.borrow_mut();
+                let mut unbound = Vec::new();
+                nodes.retain(|(node, _)| {
+                    if node.name == name {
+                        unbound.push(node.clone());
+                        false
+                    } else {
+                        true
+                    }
+                });
+                unbound
+            };
+            for node in &unbound {
+                if node.muted.get() {
+                    node_set_mute(node, false, &state.pods, &state.bus);
+                }
+            }
+            if !unbound.is_empty() {
+                if let Some(core) = state.core.borrow().clone() {
+                    do_roundtrip(&core).await;
+                }
+            }
+            Response::Ok
+        }
+        Request::SetReleaseDelay { millis } => {
+            state.default_release_delay.set(millis);
+            Response::Ok
+        }
+    }
+}
+
+fn matches_target(node: &crate::Node, target: &NodeTarget) -> bool {
+    match target {
+        NodeTarget::Name(name) => node.name == *name,
+        NodeTarget::GlobalId(id) => node.global_id == *id,
+    }
+}
+
+async fn respond(stream: &mut UnixStream, response: &Response) -> io::Result<()> {
+    let bytes = bincode::serialize(response).expect("Response is always serializable");
+    write_frame(stream, &bytes).await
+}
+
+/// Reads one length-prefixed frame (`u32` little-endian length + payload). Returns `None` on
+/// a clean EOF before any bytes of a new frame arrive.
+async fn read_frame(stream: &mut UnixStream) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+async fn write_frame(stream: &mut UnixStream, bytes: &[u8]) -> io::Result<()> {
+    stream.write_all(&(bytes.len() as u32).to_le_bytes()).await?;
+    stream.write_all(bytes).await?;
+    stream.flush().await
+}
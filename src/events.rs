@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_executor::LocalExecutor;
+use async_io::{Async, Timer};
+use evdev::{Device, InputEventKind, Key};
+
+use crate::bus::EventSys;
+use crate::taskgroup::Stopper;
+use crate::{do_roundtrip, node_set_mute, CoreHandle, KeyType, MutePods, Nodes};
+
+/// Watches one evdev device for key events and mutes/unmutes the nodes bound to them.
+///
+/// Runs until the device is unplugged or the [`Stopper`] fires (the daemon is shutting down),
+/// instead of the old per-keyboard thread just blocking forever on `fetch_events()`. Any other
+/// read error is logged and treated the same as an unplug rather than panicking the whole
+/// daemon over one misbehaving keyboard.
+pub async fn watch_device(
+    path: PathBuf,
+    dev: Device,
+    nodes: Nodes,
+    pods: Arc<MutePods>,
+    bus: EventSys,
+    core_handle: CoreHandle,
+    ex: Rc<LocalExecutor<'static>>,
+    stopper: Stopper,
+) {
+    let dev_name = String::from(dev.name().unwrap_or(dev.physical_path().unwrap_or("unknown name")));
+    let dev = match Async::new(dev) {
+        Ok(dev) => dev,
+        Err(err) => {
+            eprintln!("failed to register \"{}\" with the reactor: {}", dev_name, err);
+            return;
+        }
+    };
+
+    let mut toggle_states = HashMap::<u32, bool>::new();
+    // Cancellable release-delay timers, one per node whose HOLD key was released; dropping
+    // the Task (removing it on a fresh press, or replacing it on a fresh release) cancels it.
+    let mut pending_mutes = HashMap::<u32, async_executor::Task<()>>::new();
+
+    loop {
+        enum Event {
+            Readable(io::Result<()>),
+            Stop,
+        }
+        let event = futures_lite::future::or(
+            async { Event::Readable(dev.readable().await) },
+            async { stopper.stopped().await; Event::Stop },
+        ).await;
+        match event {
+            Event::Readable(Err(err)) => {
+                eprintln!("Unexpected error polling \"{}\" ({}): {}", dev_name, path.display(), err);
+                return;
+            }
+            Event::Readable(Ok(())) => {}
+            Event::Stop => return,
+        }
+
+        let events = unsafe { dev.get_mut() }.fetch_events();
+        let events = match events {
+            Ok(events) => events,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) if err.raw_os_error() == Some(libc::ENODEV) => {
+                // device was removed
+                return;
+            }
+            Err(err) => {
+                eprintln!("Unexpected error fetching events from \"{}\" ({}): {}", dev_name, path.display(), err);
+                return;
+            }
+        };
+
+        for event in events {
+            if let InputEventKind::Key(event_key) = event.kind() {
+                handle_key_event(event_key, event.value(), &nodes, &pods, &bus, &core_handle, &ex, &mut toggle_states, &mut pending_mutes);
+            }
+        }
+    }
+}
+
+/// Flushes a mute/unmute queued on the hot key path to PipeWire. `set_mute` only queues a
+/// `set_param`; without this roundtrip (run on its own detached task so a keypress never
+/// blocks on it) the change wouldn't actually reach PipeWire until something else happened to
+/// trigger one.
+fn flush_mute(core_handle: &CoreHandle, ex: &Rc<LocalExecutor<'static>>) {
+    let core_handle = core_handle.clone();
+    ex.spawn(async move {
+        if let Some(core) = core_handle.borrow().clone() {
+            do_roundtrip(&core).await;
+        }
+    }).detach();
+}
+
+fn handle_key_event(
+    event_key: Key,
+    value: i32,
+    nodes: &Nodes,
+    pods: &Arc<MutePods>,
+    bus: &EventSys,
+    core_handle: &CoreHandle,
+    ex: &Rc<LocalExecutor<'static>>,
+    toggle_states: &mut HashMap<u32, bool>,
+    pending_mutes: &mut HashMap<u32, async_executor::Task<()>>,
+) {
+    for (node, (key_type, k)) in nodes.borrow().iter() {
+        if *k != event_key {
+            continue;
+        }
+        if *key_type == KeyType::HOLD {
+            match value {
+                1 => { // key down: unmute immediately, cancel any pending delayed mute
+                    pending_mutes.remove(&node.global_id);
+                    node_set_mute(node, false, pods, bus);
+                    flush_mute(core_handle, ex);
+                }
+                0 => { // key up: mute, after the node's release-delay if it has one
+                    if node.release_delay == 0 {
+                        node_set_mute(node, true, pods, bus);
+                        flush_mute(core_handle, ex);
+                    } else {
+                        let node = node.clone();
+                        let pods = pods.clone();
+                        let bus = bus.clone();
+                        let core_handle = core_handle.clone();
+                        let delay = Duration::from_millis(node.release_delay);
+                        let task = ex.spawn(async move {
+                            Timer::after(delay).await;
+                            node_set_mute(&node, true, &pods, &bus);
+                            if let Some(core) = core_handle.borrow().clone() {
+                                do_roundtrip(&core).await;
+                            }
+                        });
+                        pending_mutes.insert(node.global_id, task);
+                    }
+                }
+                _ => {}
+            }
+        } else if value == 1 { // toggle, key down
+            let state = toggle_states.entry(node.global_id).or_insert(true);
+            *state = !*state;
+            node_set_mute(node, *state, pods, bus);
+            flush_mute(core_handle, ex);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use async_executor::LocalExecutor;
+use async_io::Async;
+use inotify::{Inotify, WatchMask};
+
+use crate::bus::EventSys;
+use crate::taskgroup::{Stopper, TaskGroup};
+use crate::{events, supports_keys, CoreHandle, MutePods, Nodes};
+
+/// Watches `/dev/input` for newly created devices and spawns a [`events::watch_device`] task
+/// (through `group`) for each one that looks like a keyboard, so a keyboard plugged in after
+/// startup gets picked up the same way the ones found by the initial `evdev::enumerate()` are.
+pub async fn watch_new_keyboards(
+    group: Rc<TaskGroup>,
+    nodes: Nodes,
+    pods: Arc<MutePods>,
+    bus: EventSys,
+    core_handle: CoreHandle,
+    ex: Rc<LocalExecutor<'static>>,
+    stopper: Stopper,
+) {
+    let inotify = Inotify::init().expect("Failed to initialize inotify");
+    inotify.watches().add("/dev/input", WatchMask::CREATE)
+        .expect("Failed to watch /dev/input for new devices");
+    let async_inotify = Async::new(inotify)
+        .expect("failed to register the inotify fd with the reactor");
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        enum Event {
+            Readable(std::io::Result<()>),
+            Stop,
+        }
+        let event = futures_lite::future::or(
+            async { Event::Readable(async_inotify.readable().await) },
+            async { stopper.stopped().await; Event::Stop },
+        ).await;
+
+        let readable = match event {
+            Event::Readable(result) => result,
+            Event::Stop => return,
+        };
+        if let Err(err) = readable {
+            eprintln!("Unexpected error polling the inotify fd: {}", err);
+            return;
+        }
+
+        let events = match unsafe { async_inotify.get_mut() }.read_events(&mut buffer) {
+            Ok(events) => events,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(err) => {
+                eprintln!("Unexpected error reading inotify events: {}", err);
+                return;
+            }
+        };
+
+        for inotify_event in events {
+            let Some(name) = inotify_event.name else { continue };
+            let path = PathBuf::from("/dev/input").join(name);
+            let dev = match evdev::Device::open(&path) {
+                Ok(dev) => dev,
+                Err(_) => continue, // not a device we can open (permissions, already gone, ...)
+            };
+            if !supports_keys(&dev) {
+                continue;
+            }
+            println!("Hotplugged {} {}", path.display(), dev.physical_path().unwrap_or("unknown"));
+
+            let nodes = nodes.clone();
+            let pods = pods.clone();
+            let bus = bus.clone();
+            let core_handle = core_handle.clone();
+            let ex_for_device = ex.clone();
+            group.spawn(move |stopper| events::watch_device(path, dev, nodes, pods, bus, core_handle, ex_for_device, stopper));
+        }
+    }
+}
@@ -1,13 +1,13 @@
-use std::{cell::Cell, rc::Rc, thread};
-use std::collections::{HashMap};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ffi::{c_char, c_int, c_uchar, c_ulong, CStr, CString};
 use std::fmt::Debug;
 use std::io::Cursor;
-use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
-use std::thread::{JoinHandle, Thread};
+use std::sync::Arc;
 use std::time::Duration;
 
 use pipewire as pw;
@@ -18,25 +18,81 @@ use pipewire_sys as sys;
 // spa_interface_call_method! needs this
 use libspa_sys as spa_sys;
 
+use async_executor::LocalExecutor;
+use async_io::Async;
+use async_signal::{Signal, Signals};
+use futures_lite::future;
+use futures_lite::stream::StreamExt;
+
 use clap::{Arg, ArgAction, ArgMatches, Command};
 use libspa::pod::{Object, Property, PropertyFlags, Value};
 use libspa::pod::serialize::PodSerializer;
 use pipewire::proxy::ProxyT;
 
 
-use evdev::{Device, enumerate, InputEventKind, Key};
+use evdev::{Device, enumerate, Key};
 
+mod bus;
+mod config;
+mod control;
+mod events;
+mod hotplug;
+mod reconnect;
+mod taskgroup;
 
 #[derive(Debug)]
-struct Node {
-    global_id: u32,
-    proxy: pw::node::Node
+pub(crate) struct Node {
+    pub(crate) global_id: u32,
+    pub(crate) name: String,
+    pub(crate) proxy: pw::node::Node,
+    /// milliseconds to wait after a HOLD key is released before muting;
+    /// defaults to the global release-delay (`--release-delay`, or the config file's top-level
+    /// `release-delay` when `--config` is given) unless overridden per node in the config file
+    pub(crate) release_delay: u64,
+    pub(crate) muted: Cell<bool>,
 }
 
 unsafe impl Send for Node {}
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum KeyType {
+/// Mutes or unmutes `node`, keeps its `muted` flag in sync (so readers like the control
+/// socket's `List` command don't need a round trip to PipeWire), and publishes a
+/// [`bus::MuteChanged`] event so other parts of the program can react to the change.
+pub fn node_set_mute(node: &Node, mute: bool, pods: &MutePods, bus: &bus::EventSys) {
+    set_mute(&node.proxy, mute, pods);
+    node.muted.set(mute);
+    bus.publish(&node.global_id.to_string(), bus::MuteChanged {
+        global_id: node.global_id,
+        name: node.name.clone(),
+        muted: mute,
+    });
+}
+
+/// All currently bound nodes and the key that drives each one.
+///
+/// Shared between the registry listener and every device watcher task. There is a single
+/// executor thread now, so `Rc<RefCell<_>>` replaces the old `Arc<Mutex<_>>`.
+pub type Nodes = Rc<RefCell<Vec<(Rc<Node>, (KeyType, Key))>>>;
+
+/// The name/key mappings the registry listener binds against. Runtime-mutable so the control
+/// socket can add or remove entries without restarting the daemon. The persistent registry
+/// listener only applies new entries to nodes that appear afterwards; the control socket's
+/// `AddMapping` handler registers an extra listener to also match nodes already announced.
+pub type Mappings = Rc<RefCell<Vec<(String, (KeyType, Key))>>>;
+
+/// The `Core` of whichever PipeWire connection is currently live, or `None` while a
+/// reconnect is in progress. Consumers that need a connection (like the control socket)
+/// read through this instead of holding a `Core` directly, since the connection can be torn
+/// down and replaced underneath them.
+pub type CoreHandle = Rc<RefCell<Option<Rc<pw::Core>>>>;
+
+/// The registry of whichever PipeWire connection is currently live, or `None` while a
+/// reconnect is in progress. Mirrors [`CoreHandle`]; lets the control socket re-walk the
+/// registry's currently-known globals (e.g. to bind a node an `AddMapping` just started
+/// matching) without holding a `Registry` directly across reconnects.
+pub type RegistryHandle = Rc<RefCell<Option<Rc<pw::registry::Registry>>>>;
+
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum KeyType {
     HOLD,
     TOGGLE
 }
@@ -58,6 +114,39 @@ fn parse_args() -> ArgMatches {
             .value_name("MILLIS")
             .help("time to wait after releasing to mute")
             .default_value("0")
+        )
+        .arg(Arg::new("config")
+            .long("config")
+            .value_name("PATH")
+            .help("TOML file listing node/key mappings; overrides --node/--node-toggle")
+        )
+        .arg(Arg::new("control-socket")
+            .long("control-socket")
+            .value_name("PATH")
+            .help("Unix socket to listen on for runtime control commands (see control.rs)")
+        )
+        .arg(Arg::new("backoff-base-ms")
+            .long("backoff-base-ms")
+            .value_name("MILLIS")
+            .help("initial delay before retrying a failed PipeWire connection")
+            .default_value("100")
+        )
+        .arg(Arg::new("backoff-factor")
+            .long("backoff-factor")
+            .value_name("FACTOR")
+            .help("multiplier applied to the reconnect delay after each failed attempt")
+            .default_value("2.0")
+        )
+        .arg(Arg::new("backoff-max-ms")
+            .long("backoff-max-ms")
+            .value_name("MILLIS")
+            .help("cap on the reconnect delay")
+            .default_value("30000")
+        )
+        .arg(Arg::new("backoff-no-jitter")
+            .long("backoff-no-jitter")
+            .help("disable random jitter added to the reconnect delay")
+            .action(ArgAction::SetTrue)
         );
 
     command.get_matches()
@@ -82,8 +171,20 @@ fn create_mute_pod(mute: bool) -> Vec<u8> {
     vec_rs
 }
 
-static mut MUTE_POD: Vec<u8> = Vec::new();
-static mut UNMUTE_POD: Vec<u8> = Vec::new();
+/// Pre-serialized set_param pods for muting/unmuting, built once at startup.
+pub struct MutePods {
+    mute: Vec<u8>,
+    unmute: Vec<u8>,
+}
+
+impl MutePods {
+    fn new() -> Self {
+        MutePods {
+            mute: create_mute_pod(true),
+            unmute: create_mute_pod(false),
+        }
+    }
+}
 
 fn node_args(args: &ArgMatches, id: &str, type_: KeyType) -> Vec<(String, (KeyType, Key))> {
     if let Some(iter) = args.get_occurrences::<String>(id) {
@@ -109,105 +210,237 @@ fn get_keyboards() -> Vec<(PathBuf, Device)> {
         .collect()
 }
 
-fn event_loop(mut dev: Device, path: PathBuf, release_delay: u64, nodes: Arc<Mutex<Vec<(Node, (KeyType, Key))>>>) {
-    let mainloop = pw::MainLoop::new().expect("Failed to create PipeWire Mainloop");
-    let context = pw::Context::new(&mainloop).expect("Failed to create PipeWire Context");
-    let core = context
-        .connect(None)
-        .expect("Failed to connect to PipeWire Core");
-    let dev_name = String::from(dev.name().unwrap_or(dev.physical_path().unwrap_or("unknown name")));
-
-    let mut key_states = HashMap::<u32, bool>::new();
-    loop {
-        let events = dev.fetch_events();
-        if let Err(err) = &events {
-            if let Some(libc::ENODEV) = err.raw_os_error() {
-                // device was removed
-                return;
-            }
-            panic!("Unexpected error fetching events from \"{}\"({}), {}", dev_name, path.display(), err);
-        }
-        for event in events.unwrap() {
-            if let InputEventKind::Key(event_key) = event.kind() {
-                dbg!(event.value(), event_key);
-                let mut change = false;
-                for (node, (key_type, k)) in nodes.lock().unwrap().deref() {
-                    if event_key == *k {
-                        if *key_type == KeyType::HOLD {
-                            let mute = match event.value() {
-                                0 => true, // release
-                                1 => false, // down
-                                _ => continue
-                            };
-                            if mute && release_delay > 0 {
-                                thread::sleep(Duration::from_millis(release_delay));
-                            }
-                            set_mute(&node.proxy, mute);
-                            change = true;
-                        } else if event.value() == 1 { // toggle and key down
-                            let state = key_states.entry(node.global_id).or_insert(true);
-                            *state = !*state;
-                            set_mute(&node.proxy, *state);
-                            change = true;
-                        }
-                    }
-                }
-                if change {
-                    do_roundtrip(&mainloop, &core);
-                }
-            }
-        }
-    }
-}
-
-
 fn main() {
-    unsafe {
-        MUTE_POD = create_mute_pod(true);
-        UNMUTE_POD = create_mute_pod(false);
-    }
+    let pods = Arc::new(MutePods::new());
 
     let args = parse_args();
-    let mut pairs = node_args(&args, "node", KeyType::HOLD);
-    pairs.extend(node_args(&args, "node-toggle", KeyType::TOGGLE));
-    let release_delay = args.get_one::<String>("release-delay").unwrap().parse::<u64>()
-        .expect("failed to parse release-delay");
+    let (pairs, release_delay, release_delay_overrides, backoff) = match args.get_one::<String>("config") {
+        Some(path) => {
+            let cfg = config::load(PathBuf::from(path).as_path())
+                .unwrap_or_else(|e| panic!("{}", e));
+            (cfg.nodes, cfg.release_delay, cfg.release_delay_overrides, cfg.backoff)
+        }
+        None => {
+            let mut pairs = node_args(&args, "node", KeyType::HOLD);
+            pairs.extend(node_args(&args, "node-toggle", KeyType::TOGGLE));
+            let release_delay = args.get_one::<String>("release-delay").unwrap().parse::<u64>()
+                .expect("failed to parse release-delay");
+            let backoff = reconnect::Backoff::new(
+                Duration::from_millis(args.get_one::<String>("backoff-base-ms").unwrap().parse()
+                    .expect("failed to parse backoff-base-ms")),
+                args.get_one::<String>("backoff-factor").unwrap().parse()
+                    .expect("failed to parse backoff-factor"),
+                Duration::from_millis(args.get_one::<String>("backoff-max-ms").unwrap().parse()
+                    .expect("failed to parse backoff-max-ms")),
+                !args.get_flag("backoff-no-jitter"),
+            );
+            (pairs, release_delay, HashMap::new(), backoff)
+        }
+    };
 
     // Initialize library and get the basic structures we need.
     pw::init();
 
-    let nodes: Arc<Mutex<Vec<(Node, (KeyType, Key))>>> = Arc::new(Mutex::new(Vec::new()));
-    let nodes_clone = nodes.clone();
-    let _listener_thread = thread::spawn(move || listen_for_nodes(pairs, nodes_clone));
+    let nodes: Nodes = Rc::new(RefCell::new(Vec::new()));
+    let mappings: Mappings = Rc::new(RefCell::new(pairs));
+    let default_release_delay = Rc::new(Cell::new(release_delay));
+    let release_delay_overrides = Rc::new(RefCell::new(release_delay_overrides));
+    let control_socket = args.get_one::<String>("control-socket").cloned();
+    let bus = bus::EventSys::new();
+    let core_handle: CoreHandle = Rc::new(RefCell::new(None));
+    let registry_handle: RegistryHandle = Rc::new(RefCell::new(None));
+
+    // Single reactor for everything: the evdev devices and the PipeWire loop are both
+    // polled on this one thread, so there is no thread-per-keyboard and no global mutable state.
+    let ex: Rc<LocalExecutor<'static>> = Rc::new(LocalExecutor::new());
+
+    // Owns every long-running task (one per keyboard, the hotplug monitor, the control
+    // socket) so SIGINT/SIGTERM can stop all of them, unmute everything, and exit cleanly
+    // instead of the old `loop { pop + join }` that only ran at the very end of `main`.
+    let group = Rc::new(taskgroup::TaskGroup::new(ex.clone()));
+
+    future::block_on(ex.run(async {
+        for (path, dev) in evdev::enumerate() {
+            if !supports_keys(&dev) {
+                continue;
+            }
+            println!("{} {}", path.display(), dev.physical_path().unwrap());
+            let nodes = nodes.clone();
+            let pods = pods.clone();
+            let bus = bus.clone();
+            let core_handle = core_handle.clone();
+            let ex_for_device = ex.clone();
+            group.spawn(move |stopper| events::watch_device(path, dev, nodes, pods, bus, core_handle, ex_for_device, stopper));
+        }
 
+        {
+            let group = group.clone();
+            let nodes = nodes.clone();
+            let pods = pods.clone();
+            let bus = bus.clone();
+            let core_handle = core_handle.clone();
+            let ex_for_hotplug = ex.clone();
+            group.spawn(move |stopper| hotplug::watch_new_keyboards(group.clone(), nodes, pods, bus, core_handle, ex_for_hotplug, stopper));
+        }
 
-    let mut threads: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::new(Mutex::new(Vec::new()));
-    for (path, mut dev) in evdev::enumerate() {
-        if !supports_keys(&mut dev) {
-            continue;
+        if let Some(path) = control_socket {
+            let state = control::ControlState {
+                nodes: nodes.clone(),
+                mappings: mappings.clone(),
+                default_release_delay: default_release_delay.clone(),
+                release_delay_overrides: release_delay_overrides.clone(),
+                pods: pods.clone(),
+                core: core_handle.clone(),
+                registry: registry_handle.clone(),
+                bus: bus.clone(),
+            };
+            let ex_for_control = ex.clone();
+            group.spawn(move |stopper| control::serve(PathBuf::from(path), state, ex_for_control, stopper));
         }
-        println!("{} {}", path.display(), dev.physical_path().unwrap());
-        let nodes2 = nodes.clone();
-        threads.lock().unwrap().push(thread::spawn(move || {
-            event_loop(dev, path, release_delay, nodes2);
-        }));
-    }
-    loop {
-        let mut guard = threads.lock().unwrap();
-        let handle = guard.pop();
-        if let Some(h) = handle {
-            drop(guard);
-            h.join().unwrap();
-        } else {
-            return;
+
+        // `run_pw_session` returns whenever the PipeWire core drops; reconnect with
+        // exponential backoff and re-walk the registry so every configured node gets rebound.
+        // Runs alongside the task group rather than inside it: it owns no per-device resources
+        // that need unmuting on shutdown (`group.shutdown` unmutes every bound node directly).
+        let nodes_for_session = nodes.clone();
+        let pods_for_session = pods.clone();
+        let bus_for_session = bus.clone();
+        let core_handle_for_session = core_handle.clone();
+        let registry_handle_for_session = registry_handle.clone();
+        let ex_for_session = ex.clone();
+        let pw_session_task = ex.spawn(async move {
+            let mut backoff = backoff;
+            let stable_after = Duration::from_secs(10);
+            loop {
+                let was_stable = run_pw_session(
+                    &mappings,
+                    &default_release_delay,
+                    &release_delay_overrides,
+                    &nodes_for_session,
+                    &pods_for_session,
+                    &bus_for_session,
+                    &core_handle_for_session,
+                    &registry_handle_for_session,
+                    &ex_for_session,
+                    stable_after,
+                ).await;
+
+                if was_stable {
+                    backoff.reset();
+                }
+                eprintln!("Lost connection to PipeWire, reconnecting...");
+                backoff.wait().await;
+            }
+        });
+
+        let mut signals = Signals::new([Signal::Int, Signal::Term])
+            .expect("Failed to register SIGINT/SIGTERM handlers");
+        signals.next().await;
+        println!("Shutting down...");
+
+        // Unmute and flush to PipeWire (via `core_handle`) while `pw_session_task` - and the
+        // loop task driving the connection it holds - is still alive; only then cancel it and
+        // join the rest of the group. Cancelling first would drop the only thing dispatching
+        // PipeWire's loop, so the queued unmute params would never actually reach it.
+        group.shutdown(&nodes, &pods, &bus, &core_handle, Duration::from_secs(5)).await;
+        pw_session_task.cancel().await;
+    }));
+}
+
+/// Connects to PipeWire, binds all configured nodes, and drives the loop until the core
+/// reports an error (the daemon restarted, the socket dropped, etc) or the connection can't be
+/// established in the first place (the daemon isn't running yet). Returns whether the
+/// connection stayed up for at least `stable_after`, which the caller uses to decide whether
+/// to reset the reconnect backoff; a failed connect attempt also reports as "not stable" so the
+/// caller backs off instead of retrying in a tight loop.
+async fn run_pw_session(
+    mappings: &Mappings,
+    default_release_delay: &Rc<Cell<u64>>,
+    release_delay_overrides: &Rc<RefCell<HashMap<String, u64>>>,
+    nodes: &Nodes,
+    pods: &Arc<MutePods>,
+    bus: &bus::EventSys,
+    core_handle: &CoreHandle,
+    registry_handle: &RegistryHandle,
+    ex: &Rc<LocalExecutor<'static>>,
+    stable_after: Duration,
+) -> bool {
+    let mainloop = match pw::MainLoop::new() {
+        Ok(mainloop) => mainloop,
+        Err(err) => {
+            eprintln!("Failed to create PipeWire Mainloop: {}", err);
+            return false;
         }
-    }
+    };
+    let context = match pw::Context::new(&mainloop) {
+        Ok(context) => context,
+        Err(err) => {
+            eprintln!("Failed to create PipeWire Context: {}", err);
+            return false;
+        }
+    };
+    let core = match context.connect(None) {
+        Ok(core) => Rc::new(core),
+        Err(err) => {
+            eprintln!("Failed to connect to PipeWire core: {}", err);
+            return false;
+        }
+    };
+    *core_handle.borrow_mut() = Some(core.clone());
+
+    let registry = match core.get_registry() {
+        Ok(registry) => Rc::new(registry),
+        Err(err) => {
+            eprintln!("Failed to get PipeWire registry: {}", err);
+            *core_handle.borrow_mut() = None;
+            return false;
+        }
+    };
+    *registry_handle.borrow_mut() = Some(registry.clone());
+    nodes.borrow_mut().clear();
+
+    let (disconnected_tx, disconnected_rx) = async_channel::bounded::<()>(1);
+    let _error_listener = core
+        .add_listener_local()
+        .error(move |id, seq, res, message| {
+            eprintln!("PipeWire core error (id {id}, seq {seq}, res {res}): {message}");
+            let _ = disconnected_tx.try_send(());
+        })
+        .register();
+
+    let _registry_listener = register_node_listener(
+        &registry,
+        mappings.clone(),
+        default_release_delay.clone(),
+        release_delay_overrides.clone(),
+        nodes.clone(),
+        pods.clone(),
+        bus.clone(),
+    );
+
+    // Drive PipeWire's loop on its own task so we can just wait on the disconnect signal below.
+    let _loop_task = ex.spawn(async move {
+        pw_loop_task(&mainloop).await;
+    });
+
+    let stable = Rc::new(Cell::new(false));
+    let stable_clone = stable.clone();
+    let _stable_task = ex.spawn(async move {
+        async_io::Timer::after(stable_after).await;
+        stable_clone.set(true);
+    });
+
+    let _ = disconnected_rx.recv().await;
+
+    *core_handle.borrow_mut() = None;
+    *registry_handle.borrow_mut() = None;
+    stable.get()
 }
 
 // requires call to do_roundtrip
-fn set_mute(node: &pw::node::Node, mute: bool) {
+pub fn set_mute(node: &pw::node::Node, mute: bool, pods: &MutePods) {
     unsafe {
-        let pod = if mute { &MUTE_POD } else { &UNMUTE_POD };
+        let pod = if mute { &pods.mute } else { &pods.unmute };
 
         let ptr: &*mut sys::pw_proxy = std::mem::transmute(node.upcast_ref());
         spa::spa_interface_call_method!(
@@ -221,65 +454,89 @@ fn set_mute(node: &pw::node::Node, mute: bool) {
     }
 }
 
-fn listen_for_nodes(name_key: Vec<(String, (KeyType, Key))>, out: Arc<Mutex<Vec<(Node, (KeyType, Key))>>>) {
-    let mainloop = pw::MainLoop::new().expect("Failed to create MainLoop for listener thread");
-    let context = pw::Context::new(&mainloop).expect("Failed to create PipeWire Context");
-    let core = context
-        .connect(None)
-        .expect("Failed to connect to PipeWire Core");
-    let registry = Rc::new(core.get_registry().expect("Failed to get Registry"));
-
+pub(crate) fn register_node_listener(
+    registry: &Rc<pw::registry::Registry>,
+    mappings: Mappings,
+    default_release_delay: Rc<Cell<u64>>,
+    release_delay_overrides: Rc<RefCell<HashMap<String, u64>>>,
+    out: Nodes,
+    pods: Arc<MutePods>,
+    bus: bus::EventSys,
+) -> impl Drop {
     let registry_clone = registry.clone();
-    let _listener = registry
+    registry
         .add_listener_local()
         .global(move |global| {
             if global.props.is_none() { return }
             let props = global.props.as_ref().unwrap();
             if global.type_ != ObjectType::Node { return }
 
+            if out.borrow().iter().any(|(node, _)| node.global_id == global.id) {
+                // Already bound, e.g. by a previous listener on this same registry; a second
+                // listener (registered to retroactively match a just-added mapping) would
+                // otherwise re-announce and double-bind it.
+                return;
+            }
+
             if let Some(name) = props.get("node.name") {
-                name_key.iter().filter(|(name_in, _)| name == *name_in).for_each(|(_, key)| {
+                mappings.borrow().iter().filter(|(name_in, _)| name == *name_in).for_each(|(name_in, key)| {
                     let proxy = registry_clone.bind(global).unwrap();
-                    let node = Node {
+                    let release_delay = release_delay_overrides.borrow().get(name_in)
+                        .copied()
+                        .unwrap_or_else(|| default_release_delay.get());
+                    let node = Rc::new(Node {
                         global_id: global.id,
-                        proxy
-                    };
+                        name: name.to_string(),
+                        proxy,
+                        release_delay,
+                        muted: Cell::new(false),
+                    });
                     println!("Found {name} with id {} for key {:?}", global.id, key.1);
-                    set_mute(&node.proxy, true);
-                    //dbg!(&node);
-                    let mut vec = out.lock().unwrap();
-                    vec.push((node, *key));
+                    node_set_mute(&node, true, &pods, &bus);
+                    out.borrow_mut().push((node, *key));
                 });
             }
         })
-        .register();
+        .register()
+}
 
-    mainloop.run();
+/// A raw fd the reactor can poll for readability; we don't own it, so we never close it.
+struct BorrowedRawFd(RawFd);
+
+impl AsRawFd for BorrowedRawFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
 }
 
+/// Drives PipeWire's own loop from inside our reactor instead of giving it a dedicated
+/// thread with `mainloop.run()`. PipeWire exposes the loop as a pollable fd
+/// (`pw_loop_get_fd`); we wait for it to become readable and hand control back to
+/// `pw_loop_iterate` to dispatch whatever is pending, forever.
+async fn pw_loop_task(mainloop: &pw::MainLoop) {
+    let fd = unsafe { sys::pw_loop_get_fd((*mainloop.as_raw()).loop_) };
+    let async_fd = Async::new(BorrowedRawFd(fd))
+        .expect("failed to register the PipeWire loop fd with the reactor");
+    loop {
+        async_fd.readable().await.expect("PipeWire loop fd error");
+        unsafe { sys::pw_loop_iterate((*mainloop.as_raw()).loop_, 0); }
+    }
+}
 
 /// Do a single roundtrip to process all events.
 /// See the example in roundtrip.rs for more details on this.
-fn do_roundtrip(mainloop: &pw::MainLoop, core: &pw::Core) {
-    let done = Rc::new(Cell::new(false));
-    let done_clone = done.clone();
-    let loop_clone = mainloop.clone();
-
-    // Trigger the sync event. The server's answer won't be processed until we start the main loop,
-    // so we can safely do this before setting up a callback. This lets us avoid using a Cell.
+pub async fn do_roundtrip(core: &pw::Core) {
+    let (tx, rx) = async_channel::bounded(1);
     let pending = core.sync(0).expect("sync failed");
 
     let _listener_core = core
         .add_listener_local()
         .done(move |id, seq| {
             if id == pw::PW_ID_CORE && seq == pending {
-                done_clone.set(true);
-                loop_clone.quit();
+                let _ = tx.try_send(());
             }
         })
         .register();
 
-    while !done.get() {
-        mainloop.run();
-    }
+    rx.recv().await.expect("PipeWire core sync channel closed before completion");
 }
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use async_io::Timer;
+use rand::Rng;
+
+/// Exponential backoff with jitter for the PipeWire reconnect loop.
+///
+/// Starts at `base`, multiplies by `factor` after every failed/dropped connection up to
+/// `max`, and (if `jitter` is enabled) adds a random extra delay in `[0, current)` so that
+/// several instances reconnecting at once don't all hammer the PipeWire socket in lockstep.
+pub struct Backoff {
+    base: Duration,
+    factor: f64,
+    max: Duration,
+    jitter: bool,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, factor: f64, max: Duration, jitter: bool) -> Self {
+        Backoff { base, factor, max, jitter, current: base }
+    }
+
+    /// Back to the base delay; call this once a connection has proven itself stable.
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+
+    pub fn base(&self) -> Duration {
+        self.base
+    }
+
+    pub fn factor(&self) -> f64 {
+        self.factor
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn jitter(&self) -> bool {
+        self.jitter
+    }
+
+    /// Sleeps for the current delay (plus jitter), then grows the delay for next time.
+    pub async fn wait(&mut self) {
+        let delay = if self.jitter {
+            self.current + self.jitter_amount()
+        } else {
+            self.current
+        };
+        Timer::after(delay).await;
+
+        let next = self.current.mul_f64(self.factor);
+        self.current = next.min(self.max);
+    }
+
+    fn jitter_amount(&self) -> Duration {
+        let fraction: f64 = rand::thread_rng().gen_range(0.0..1.0);
+        self.current.mul_f64(fraction)
+    }
+}
+
+impl Default for Backoff {
+    /// 100ms base, doubling, capped at 30s, with jitter enabled.
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(30), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    #[test]
+    fn wait_grows_current_by_factor_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_millis(350), false);
+
+        block_on(backoff.wait());
+        assert_eq!(backoff.current, Duration::from_millis(200));
+
+        block_on(backoff.wait());
+        assert_eq!(backoff.current, Duration::from_millis(350)); // capped at max, not 400ms
+
+        block_on(backoff.wait());
+        assert_eq!(backoff.current, Duration::from_millis(350)); // stays capped
+    }
+
+    #[test]
+    fn reset_returns_to_base() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(30), false);
+        block_on(backoff.wait());
+        assert_ne!(backoff.current, backoff.base);
+
+        backoff.reset();
+        assert_eq!(backoff.current, backoff.base);
+    }
+
+    #[test]
+    fn jitter_amount_stays_within_current() {
+        let backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(30), true);
+        for _ in 0..100 {
+            let jitter = backoff.jitter_amount();
+            assert!(jitter < backoff.current);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+use async_executor::{LocalExecutor, Task};
+use async_io::Timer;
+
+use crate::bus::EventSys;
+use crate::{do_roundtrip, node_set_mute, CoreHandle, MutePods, Nodes};
+
+/// Handed to every task spawned through a [`TaskGroup`] so it can notice a shutdown request
+/// cooperatively, instead of being killed outright.
+#[derive(Clone)]
+pub struct Stopper {
+    rx: async_channel::Receiver<()>,
+}
+
+impl Stopper {
+    /// Resolves once the group starts shutting down. Race this against whatever the task is
+    /// normally waiting on (a readable fd, an accept(), ...).
+    pub async fn stopped(&self) {
+        let _ = self.rx.recv().await;
+    }
+}
+
+struct Child {
+    task: Task<()>,
+    stop_tx: async_channel::Sender<()>,
+}
+
+/// Owns every long-running task the daemon spawns (one per keyboard, the hotplug monitor,
+/// the control socket) so a single SIGINT/SIGTERM handler can stop all of them, unmute
+/// whatever they left muted, and exit cleanly instead of the old `loop { pop + join }` that
+/// only ever ran at the very end of `main`.
+pub struct TaskGroup {
+    ex: Rc<LocalExecutor<'static>>,
+    children: RefCell<Vec<Child>>,
+}
+
+impl TaskGroup {
+    pub fn new(ex: Rc<LocalExecutor<'static>>) -> Self {
+        TaskGroup { ex, children: RefCell::new(Vec::new()) }
+    }
+
+    /// Spawns a task, at startup or later when a device is hotplugged. `make_future` receives
+    /// the [`Stopper`] the task should watch for a shutdown signal.
+    pub fn spawn<F, Fut>(&self, make_future: F)
+    where
+        F: FnOnce(Stopper) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let (stop_tx, stop_rx) = async_channel::bounded(1);
+        let task = self.ex.spawn(make_future(Stopper { rx: stop_rx }));
+        self.children.borrow_mut().push(Child { task, stop_tx });
+    }
+
+    /// Unmutes all currently-muted nodes so the user isn't left permanently muted on exit,
+    /// then signals every child to stop and waits for them to finish (up to `timeout`).
+    ///
+    /// `node_set_mute` only queues a `set_param` on the node's proxy; it takes a roundtrip to
+    /// actually reach PipeWire. `core_handle` must still point at a live core (i.e. this must
+    /// run before the session driving it is torn down) or the unmute never lands.
+    pub async fn shutdown(&self, nodes: &Nodes, pods: &MutePods, bus: &EventSys, core_handle: &CoreHandle, timeout: Duration) {
+        for (node, _) in nodes.borrow().iter() {
+            if node.muted.get() {
+                node_set_mute(node, false, pods, bus);
+            }
+        }
+        if let Some(core) = core_handle.borrow().clone() {
+            do_roundtrip(&core).await;
+        }
+
+        let children: Vec<Child> = self.children.borrow_mut().drain(..).collect();
+        for child in &children {
+            let _ = child.stop_tx.try_send(());
+        }
+
+        let join_all = async {
+            for child in children {
+                child.task.await;
+            }
+        };
+        futures_lite::future::or(join_all, async { Timer::after(timeout).await; }).await;
+    }
+}